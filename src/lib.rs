@@ -21,6 +21,7 @@
 #[cfg(not(target_os = "linux"))]
 compile_error!("Netlink only works on Linux");
 
+use std::collections::HashMap;
 use std::os::unix::io::AsRawFd;
 
 use anyhow::{anyhow, Context};
@@ -77,12 +78,42 @@ impl_var!(
 );
 impl NlAttrType for NbdSock {}
 
+impl_var!(
+    NbdDeviceItem, u16,
+    Unspec => 0,
+    Item => 1
+);
+impl NlAttrType for NbdDeviceItem {}
+
+impl_var!(
+    NbdDevice, u16,
+    Unspec => 0,
+    Index => 1,
+    Connected => 2
+);
+impl NlAttrType for NbdDevice {}
+
 const HAS_FLAGS: u64 = 1 << 0;
 const READ_ONLY: u64 = 1 << 1;
 const CAN_MULTI_CONN: u64 = 1 << 8;
 
 const NBD_CFLAG_DISCONNECT_ON_CLOSE: u64 = 1 << 1;
 
+const NBD_GENL_MCGRP_NAME: &str = "nbd_mc_group";
+
+fn attr<T: NlAttrType, P: Nl>(t: T, p: P) -> Result<Nlattr<T, Buffer>, NlError> {
+    Nlattr::new(None, false, false, t, p)
+}
+
+/// The status of a single NBD device, as reported by [`NBD::status`].
+#[derive(Debug, Clone, Copy)]
+pub struct NbdDeviceStatus {
+    /// The index of the device.
+    pub index: u32,
+    /// Whether the device currently has a live connection.
+    pub connected: bool,
+}
+
 /// An NBD netlink socket, usable to set up NBD devices.
 pub struct NBD {
     nl: NlSocketHandle,
@@ -102,27 +133,100 @@ impl NBD {
             .context("Could not resolve the NBD generic netlink family")?;
         Ok(Self { nl, nbd_family })
     }
+
+    /// Tell the kernel to disconnect the NBD device with the given index.
+    pub fn disconnect(&mut self, index: u32) -> anyhow::Result<()> {
+        let mut attrs = GenlBuffer::new();
+        attrs.push(attr(NbdAttr::Index, index)?);
+
+        let genl_header = Genlmsghdr::new(NbdCmd::Disconnect, 1, attrs);
+        let nl_header = Nlmsghdr::new(
+            None,
+            self.nbd_family,
+            NlmFFlags::new(&[NlmF::Request, NlmF::Ack]),
+            None,
+            None,
+            NlPayload::Payload(genl_header),
+        );
+        self.nl.send(nl_header)?;
+        let _: Nlmsghdr<u16, Genlmsghdr<NbdCmd, NbdAttr>> = self
+            .nl
+            .recv()?
+            .ok_or_else(|| anyhow!("Error disconnecting NBD device: No response received"))?;
+        Ok(())
+    }
+
+    /// Query the kernel for the status of NBD devices.
+    ///
+    /// If `index` is `Some`, only that device's status is returned. If `index` is `None`, the
+    /// status of every NBD device known to the kernel is returned.
+    pub fn status(&mut self, index: Option<u32>) -> anyhow::Result<Vec<NbdDeviceStatus>> {
+        let mut attrs = GenlBuffer::new();
+        if let Some(index) = index {
+            attrs.push(attr(NbdAttr::Index, index)?);
+        }
+
+        let genl_header = Genlmsghdr::new(NbdCmd::Status, 1, attrs);
+        let nl_header = Nlmsghdr::new(
+            None,
+            self.nbd_family,
+            NlmFFlags::new(&[NlmF::Request]),
+            None,
+            None,
+            NlPayload::Payload(genl_header),
+        );
+        self.nl.send(nl_header)?;
+        let response: Nlmsghdr<u16, Genlmsghdr<NbdCmd, NbdAttr>> = self
+            .nl
+            .recv()?
+            .ok_or_else(|| anyhow!("Error querying NBD status: No response received"))?;
+        let mut handle = response.get_payload()?.get_attr_handle();
+        let device_list = handle.get_nested_attributes::<NbdDeviceItem>(NbdAttr::DeviceList)?;
+
+        let mut devices = Vec::new();
+        for item in device_list.get_attrs() {
+            let item_handle = item.get_attr_handle::<NbdDevice>()?;
+            let index = item_handle.get_attr_payload_as::<u32>(NbdDevice::Index)?;
+            let connected = item_handle.get_attr_payload_as::<u8>(NbdDevice::Connected)? != 0;
+            devices.push(NbdDeviceStatus { index, connected });
+        }
+        Ok(devices)
+    }
 }
 
 /// A builder for an NBD connect call.
 pub struct NBDConnect {
+    index: Option<u32>,
     size_bytes: u64,
     block_size_bytes: u64,
     server_flags: u64,
     client_flags: u64,
+    timeout: Option<u64>,
+    dead_conn_timeout: Option<u64>,
 }
 
 impl NBDConnect {
     /// Create a new NBDConnect builder.
     pub fn new() -> Self {
         Self {
+            index: None,
             size_bytes: 0,
             block_size_bytes: 4096,
             server_flags: HAS_FLAGS,
             client_flags: 0,
+            timeout: None,
+            dead_conn_timeout: None,
         }
     }
 
+    /// Connect to a specific NBD device index, such as to reconnect to a known `/dev/nbdX` after
+    /// a crash where the device node and its consumers still exist. If not specified, the kernel
+    /// allocates a free index.
+    pub fn index(&mut self, index: u32) -> &mut Self {
+        self.index = Some(index);
+        self
+    }
+
     /// Set the size for the NBD device, in bytes. Defaults to 0 if not specified.
     pub fn size_bytes(&mut self, bytes: u64) -> &mut Self {
         self.size_bytes = bytes;
@@ -165,6 +269,20 @@ impl NBDConnect {
         self
     }
 
+    /// Set the request timeout, in seconds, the kernel uses to detect hung I/O. Left at the
+    /// kernel default if not specified.
+    pub fn timeout(&mut self, seconds: u64) -> &mut Self {
+        self.timeout = Some(seconds);
+        self
+    }
+
+    /// Set the dead connection timeout, in seconds, the kernel uses to decide when to drop a
+    /// silent socket. Left at the kernel default if not specified.
+    pub fn dead_conn_timeout(&mut self, seconds: u64) -> &mut Self {
+        self.dead_conn_timeout = Some(seconds);
+        self
+    }
+
     /// Tell the kernel to connect an NBD device to the specified sockets.
     ///
     /// Returns the index of the newly connected NBD device.
@@ -173,9 +291,6 @@ impl NBDConnect {
         nbd: &mut NBD,
         sockets: impl IntoIterator<Item = &'a (impl AsRawFd + 'a)>,
     ) -> anyhow::Result<u32> {
-        fn attr<T: NlAttrType, P: Nl>(t: T, p: P) -> Result<Nlattr<T, Buffer>, NlError> {
-            Nlattr::new(None, false, false, t, p)
-        }
         let mut sockets_attr = Nlattr::new(None, true, false, NbdAttr::Sockets, Buffer::new())?;
         for socket in sockets {
             sockets_attr.add_nested_attribute(&Nlattr::new(
@@ -187,10 +302,19 @@ impl NBDConnect {
             )?)?;
         }
         let mut attrs = GenlBuffer::new();
+        if let Some(index) = self.index {
+            attrs.push(attr(NbdAttr::Index, index)?);
+        }
         attrs.push(attr(NbdAttr::SizeBytes, self.size_bytes)?);
         attrs.push(attr(NbdAttr::BlockSizeBytes, self.block_size_bytes)?);
         attrs.push(attr(NbdAttr::ServerFlags, self.server_flags)?);
         attrs.push(attr(NbdAttr::ClientFlags, self.client_flags)?);
+        if let Some(timeout) = self.timeout {
+            attrs.push(attr(NbdAttr::Timeout, timeout)?);
+        }
+        if let Some(dead_conn_timeout) = self.dead_conn_timeout {
+            attrs.push(attr(NbdAttr::DeadConnTimeout, dead_conn_timeout)?);
+        }
         attrs.push(sockets_attr);
 
         let genl_header = Genlmsghdr::new(NbdCmd::Connect, 1, attrs);
@@ -212,3 +336,203 @@ impl NBDConnect {
         Ok(index)
     }
 }
+
+/// A builder for an NBD reconfigure call.
+///
+/// Reconfiguring an existing device lets a client replace its sockets after a dropped
+/// connection, or resize the exported device, without tearing down the block device.
+pub struct NBDReconfigure {
+    index: u32,
+    size_bytes: Option<u64>,
+    server_flags: u64,
+    timeout: Option<u64>,
+}
+
+impl NBDReconfigure {
+    /// Create a new NBDReconfigure builder targeting the NBD device with the given index.
+    pub fn new(index: u32) -> Self {
+        Self {
+            index,
+            size_bytes: None,
+            server_flags: HAS_FLAGS,
+            timeout: None,
+        }
+    }
+
+    /// Set the new size for the NBD device, in bytes. Leaves the size unchanged if not specified.
+    pub fn size_bytes(&mut self, bytes: u64) -> &mut Self {
+        self.size_bytes = Some(bytes);
+        self
+    }
+
+    /// Set the raw server flags, overriding any previously set via `read_only`/`can_multi_conn`.
+    ///
+    /// Used by [`NBDResilient`] to resend the flags a device was originally connected with.
+    fn server_flags(&mut self, server_flags: u64) -> &mut Self {
+        self.server_flags = server_flags;
+        self
+    }
+
+    /// Set the device as read-only.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        if read_only {
+            self.server_flags |= READ_ONLY;
+        } else {
+            self.server_flags &= !READ_ONLY;
+        }
+        self
+    }
+
+    /// Set the device as allowing multiple concurrent socket connections.
+    pub fn can_multi_conn(&mut self, can_multi_conn: bool) -> &mut Self {
+        if can_multi_conn {
+            self.server_flags |= CAN_MULTI_CONN;
+        } else {
+            self.server_flags &= !CAN_MULTI_CONN;
+        }
+        self
+    }
+
+    /// Set the request timeout, in seconds, used to detect hung I/O. Leaves the timeout
+    /// unchanged if not specified.
+    pub fn timeout(&mut self, seconds: u64) -> &mut Self {
+        self.timeout = Some(seconds);
+        self
+    }
+
+    /// Tell the kernel to reconfigure the targeted NBD device with a new socket list and the
+    /// parameters set on this builder.
+    ///
+    /// This can be used to swap in fresh connections after a dropped link, or to resize the
+    /// device, without disconnecting it.
+    pub fn reconfigure<'a>(
+        &self,
+        nbd: &mut NBD,
+        sockets: impl IntoIterator<Item = &'a (impl AsRawFd + 'a)>,
+    ) -> anyhow::Result<()> {
+        let mut sockets_attr = Nlattr::new(None, true, false, NbdAttr::Sockets, Buffer::new())?;
+        for socket in sockets {
+            sockets_attr.add_nested_attribute(&Nlattr::new(
+                None,
+                true,
+                false,
+                NbdSockItem::Item,
+                attr(NbdSock::Fd, socket.as_raw_fd())?,
+            )?)?;
+        }
+        let mut attrs = GenlBuffer::new();
+        attrs.push(attr(NbdAttr::Index, self.index)?);
+        if let Some(size_bytes) = self.size_bytes {
+            attrs.push(attr(NbdAttr::SizeBytes, size_bytes)?);
+        }
+        attrs.push(attr(NbdAttr::ServerFlags, self.server_flags)?);
+        if let Some(timeout) = self.timeout {
+            attrs.push(attr(NbdAttr::Timeout, timeout)?);
+        }
+        attrs.push(sockets_attr);
+
+        let genl_header = Genlmsghdr::new(NbdCmd::Reconfigure, 1, attrs);
+        let nl_header = Nlmsghdr::new(
+            None,
+            nbd.nbd_family,
+            NlmFFlags::new(&[NlmF::Request, NlmF::Ack]),
+            None,
+            None,
+            NlPayload::Payload(genl_header),
+        );
+        nbd.nl.send(nl_header)?;
+        let _: Nlmsghdr<u16, Genlmsghdr<NbdCmd, NbdAttr>> = nbd
+            .nl
+            .recv()?
+            .ok_or_else(|| anyhow!("Error reconfiguring NBD device: No response received"))?;
+        Ok(())
+    }
+}
+
+/// A source of fresh sockets used by [`NBDResilient`] to replace a connection the kernel reports
+/// as dead.
+pub trait SocketFactory {
+    /// The concrete socket type produced by this factory.
+    type Socket: AsRawFd;
+
+    /// Dial a new socket to the NBD server, to replace one whose link died.
+    fn connect(&mut self) -> anyhow::Result<Self::Socket>;
+}
+
+/// A resilient, multi-connection NBD client that reconnects automatically.
+///
+/// Given several sockets and `can_multi_conn(true)`, `NBDResilient` connects a device, then
+/// watches for the kernel's `NbdCmd::LinkDead` notification naming the device whose connection
+/// died, dials a replacement socket from a [`SocketFactory`], and feeds it back through
+/// [`NBDReconfigure`]. This gives callers the graceful single-path-failure reconnect behavior
+/// other NBD clients implement by hand, without leaving the block device itself.
+pub struct NBDResilient<F: SocketFactory> {
+    nbd: NBD,
+    socket_factory: F,
+    server_flags: u64,
+    live_sockets: HashMap<u32, usize>,
+}
+
+impl<F: SocketFactory> NBDResilient<F> {
+    /// Connect a new resilient NBD device, dialing `socket_count` sockets from `socket_factory`
+    /// through `connect`. Sets `can_multi_conn(true)` on `connect` automatically.
+    pub fn connect(
+        mut nbd: NBD,
+        connect: &mut NBDConnect,
+        socket_count: usize,
+        mut socket_factory: F,
+    ) -> anyhow::Result<Self> {
+        connect.can_multi_conn(true);
+
+        let group = nbd
+            .nl
+            .resolve_nl_mcast_group("nbd", NBD_GENL_MCGRP_NAME)
+            .context("Could not resolve the NBD multicast group")?;
+        nbd.nl.add_mcast_membership(&[group])?;
+
+        let mut sockets = Vec::with_capacity(socket_count);
+        for _ in 0..socket_count {
+            sockets.push(socket_factory.connect()?);
+        }
+        let index = connect.connect(&mut nbd, &sockets)?;
+
+        let mut live_sockets = HashMap::new();
+        live_sockets.insert(index, socket_count);
+        Ok(Self {
+            nbd,
+            socket_factory,
+            server_flags: connect.server_flags,
+            live_sockets,
+        })
+    }
+
+    /// Block, watching for `NbdCmd::LinkDead` notifications for the devices this client manages,
+    /// and reconnect a fresh socket from the socket factory whenever one arrives.
+    ///
+    /// Returns only on error; run it on a dedicated thread to reconnect in the background.
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        loop {
+            let message: Nlmsghdr<u16, Genlmsghdr<NbdCmd, NbdAttr>> = self
+                .nbd
+                .nl
+                .recv()?
+                .ok_or_else(|| anyhow!("Error watching NBD events: No response received"))?;
+            let payload = message.get_payload()?;
+            if payload.cmd != NbdCmd::LinkDead {
+                continue;
+            }
+            let handle = payload.get_attr_handle();
+            let index = handle.get_attr_payload_as::<u32>(NbdAttr::Index)?;
+            let count = match self.live_sockets.get(&index) {
+                Some(&count) => count,
+                None => continue,
+            };
+
+            let socket = self.socket_factory.connect()?;
+            NBDReconfigure::new(index)
+                .server_flags(self.server_flags)
+                .reconfigure(&mut self.nbd, &[socket])?;
+            self.live_sockets.insert(index, count);
+        }
+    }
+}